@@ -1,11 +1,20 @@
-use glutin::event::{ElementState, Event, KeyEvent, WindowEvent};
-use glutin::keyboard::Key;
+use std::collections::{HashMap, HashSet};
+
+use glutin::event::{ElementState, Event, Ime, KeyEvent, WindowEvent};
+use glutin::keyboard::{Key, KeyCode, PhysicalKey};
 
 use winit::platform::modifier_supplement::KeyEventExtModifierSupplement;
 
 use crate::bridge::UiCommand;
 use crate::channel_utils::LoggingTx;
 
+// State of the editor-side keyboard macro facility (independent of Neovim's own `q`/`@`).
+enum RecordState {
+    Idle,
+    Recording(Vec<String>),
+    Replaying { events: Vec<String>, idx: usize },
+}
+
 #[cfg(not(target_os = "windows"))]
 fn use_logo(logo: bool) -> bool {
     logo
@@ -56,6 +65,78 @@ fn is_control_key(key: Key<'static>) -> Option<&str> {
     }
 }
 
+// Maps a physical key to the text it produces on a US QWERTY layout.
+fn physical_key_to_text(physical_key: PhysicalKey) -> Option<&'static str> {
+    match physical_key {
+        PhysicalKey::Code(code) => match code {
+            KeyCode::KeyA => Some("a"),
+            KeyCode::KeyB => Some("b"),
+            KeyCode::KeyC => Some("c"),
+            KeyCode::KeyD => Some("d"),
+            KeyCode::KeyE => Some("e"),
+            KeyCode::KeyF => Some("f"),
+            KeyCode::KeyG => Some("g"),
+            KeyCode::KeyH => Some("h"),
+            KeyCode::KeyI => Some("i"),
+            KeyCode::KeyJ => Some("j"),
+            KeyCode::KeyK => Some("k"),
+            KeyCode::KeyL => Some("l"),
+            KeyCode::KeyM => Some("m"),
+            KeyCode::KeyN => Some("n"),
+            KeyCode::KeyO => Some("o"),
+            KeyCode::KeyP => Some("p"),
+            KeyCode::KeyQ => Some("q"),
+            KeyCode::KeyR => Some("r"),
+            KeyCode::KeyS => Some("s"),
+            KeyCode::KeyT => Some("t"),
+            KeyCode::KeyU => Some("u"),
+            KeyCode::KeyV => Some("v"),
+            KeyCode::KeyW => Some("w"),
+            KeyCode::KeyX => Some("x"),
+            KeyCode::KeyY => Some("y"),
+            KeyCode::KeyZ => Some("z"),
+            KeyCode::Digit0 => Some("0"),
+            KeyCode::Digit1 => Some("1"),
+            KeyCode::Digit2 => Some("2"),
+            KeyCode::Digit3 => Some("3"),
+            KeyCode::Digit4 => Some("4"),
+            KeyCode::Digit5 => Some("5"),
+            KeyCode::Digit6 => Some("6"),
+            KeyCode::Digit7 => Some("7"),
+            KeyCode::Digit8 => Some("8"),
+            KeyCode::Digit9 => Some("9"),
+            KeyCode::Comma => Some(","),
+            KeyCode::Period => Some("."),
+            KeyCode::Slash => Some("/"),
+            KeyCode::Semicolon => Some(";"),
+            KeyCode::Quote => Some("'"),
+            KeyCode::BracketLeft => Some("["),
+            KeyCode::BracketRight => Some("]"),
+            KeyCode::Backslash => Some("\\"),
+            KeyCode::Minus => Some("-"),
+            KeyCode::Equal => Some("="),
+            KeyCode::Backquote => Some("`"),
+            _ => None,
+        },
+        PhysicalKey::Unidentified(_) => None,
+    }
+}
+
+// On Windows and many European layouts, AltGr is reported as Ctrl+Alt. When that combination
+// still produced printable text (e.g. `@`, `{`, `|`), it's an AltGr-composed character rather
+// than a genuine `<C-M-...>` chord, so the control and alt prefixes should be dropped.
+fn strip_altgr(ctrl: bool, alt: bool, produced_text: Option<&str>) -> (bool, bool) {
+    let is_altgr_composed = ctrl
+        && alt
+        && matches!(produced_text, Some(text) if text.chars().all(|c| !c.is_control()) && !text.is_empty());
+
+    if is_altgr_composed {
+        (false, false)
+    } else {
+        (ctrl, alt)
+    }
+}
+
 fn is_special(text: &str) -> Option<&str> {
     match text {
         " " => Some("Space"),
@@ -75,6 +156,14 @@ pub struct KeyboardManager {
     logo: bool,
     ignore_input_this_frame: bool,
     queued_key_events: Vec<KeyEvent>,
+    // Physical modifier keys currently held, so releasing one side (e.g. ControlLeft) doesn't
+    // clear a modifier still held via the other side (ControlRight).
+    held_modifier_keys: HashSet<KeyCode>,
+    // While true, an IME preedit is in progress and raw keypresses are suppressed in favor
+    // of the eventual `Ime::Commit`.
+    ime_active: bool,
+    record_state: RecordState,
+    macros: HashMap<String, Vec<String>>,
 }
 
 impl KeyboardManager {
@@ -86,9 +175,65 @@ impl KeyboardManager {
             logo: false,
             ignore_input_this_frame: false,
             queued_key_events: Vec::new(),
+            held_modifier_keys: HashSet::new(),
+            ime_active: false,
+            record_state: RecordState::Idle,
+            macros: HashMap::new(),
         }
     }
 
+    /// Starts capturing every keybinding string produced from now on into a macro buffer,
+    /// discarding any macro that was previously being recorded.
+    pub fn start_recording(&mut self) {
+        self.record_state = RecordState::Recording(Vec::new());
+    }
+
+    /// Stops recording and saves the captured keybinding strings under `name` for later replay.
+    /// Does nothing if a recording wasn't in progress.
+    pub fn stop_recording(&mut self, name: String) {
+        if !matches!(self.record_state, RecordState::Recording(_)) {
+            return;
+        }
+
+        let previous_state = std::mem::replace(&mut self.record_state, RecordState::Idle);
+        if let RecordState::Recording(events) = previous_state {
+            self.macros.insert(name, events);
+        }
+    }
+
+    /// Begins replaying a previously recorded macro, flushing one event per frame so
+    /// timing-sensitive Neovim commands still behave. Does nothing if no macro is saved under
+    /// `name`.
+    pub fn replay(&mut self, name: &str) {
+        if let Some(events) = self.macros.get(name) {
+            self.record_state = RecordState::Replaying {
+                events: events.clone(),
+                idx: 0,
+            };
+        }
+    }
+
+    fn send_text(&mut self, text: &str) {
+        let keybinding_string = if let Some(escaped_text) = is_special(text) {
+            self.format_keybinding_string(true, escaped_text)
+        } else {
+            self.format_keybinding_string(false, text)
+        };
+
+        self.send_keybinding_string(keybinding_string);
+    }
+
+    // Sends a keybinding string to Neovim, also recording it if a macro is being captured.
+    fn send_keybinding_string(&mut self, keybinding_string: String) {
+        if let RecordState::Recording(events) = &mut self.record_state {
+            events.push(keybinding_string.clone());
+        }
+
+        self.command_sender
+            .send(UiCommand::Keyboard(keybinding_string))
+            .expect("Could not send keyboard ui command");
+    }
+
     fn format_keybinding_string(&self, special: bool, text: &str) -> String {
         let special = special || self.ctrl || self.alt || self.logo;
 
@@ -110,6 +255,13 @@ impl KeyboardManager {
                 // The window was just focused, so ignore keyboard events that were submitted this
                 // frame.
                 self.ignore_input_this_frame = *focused;
+
+                // Modifiers released while another window had focus never reach
+                // ModifiersChanged, so reset them here to avoid a stuck chord.
+                self.ctrl = false;
+                self.alt = false;
+                self.logo = false;
+                self.held_modifier_keys.clear();
             }
             Event::WindowEvent {
                 event:
@@ -118,10 +270,61 @@ impl KeyboardManager {
                     },
                 ..
             } => {
+                // Track each modifier key's own press/release rather than trusting only the
+                // aggregate ModifiersChanged event, but only clear a modifier once neither of
+                // its two physical keys is still held.
+                if let PhysicalKey::Code(code) = key_event.physical_key {
+                    if matches!(
+                        code,
+                        KeyCode::ControlLeft
+                            | KeyCode::ControlRight
+                            | KeyCode::AltLeft
+                            | KeyCode::AltRight
+                            | KeyCode::SuperLeft
+                            | KeyCode::SuperRight
+                    ) {
+                        match key_event.state {
+                            ElementState::Pressed => {
+                                self.held_modifier_keys.insert(code);
+                            }
+                            ElementState::Released => {
+                                self.held_modifier_keys.remove(&code);
+                            }
+                        }
+
+                        self.ctrl = self.held_modifier_keys.contains(&KeyCode::ControlLeft)
+                            || self.held_modifier_keys.contains(&KeyCode::ControlRight);
+                        self.alt = self.held_modifier_keys.contains(&KeyCode::AltLeft)
+                            || self.held_modifier_keys.contains(&KeyCode::AltRight);
+                        self.logo = self.held_modifier_keys.contains(&KeyCode::SuperLeft)
+                            || self.held_modifier_keys.contains(&KeyCode::SuperRight);
+                    }
+                }
+
                 // Store the event so that we can ignore it properly if the window was just
                 // focused.
                 self.queued_key_events.push(key_event.clone());
             }
+            Event::WindowEvent {
+                event: WindowEvent::Ime(ime),
+                ..
+            } => match ime {
+                Ime::Preedit(text, _cursor) => {
+                    self.ime_active = !text.is_empty();
+                }
+                // Send the committed text, splitting multi-codepoint commits into
+                // correctly-escaped pieces.
+                Ime::Commit(text) => {
+                    self.ime_active = false;
+                    for character in text.chars() {
+                        let mut buffer = [0u8; 4];
+                        self.send_text(character.encode_utf8(&mut buffer));
+                    }
+                }
+                Ime::Enabled | Ime::Disabled => {
+                    self.ime_active = false;
+                }
+            },
             Event::WindowEvent {
                 event: WindowEvent::ModifiersChanged(modifiers),
                 ..
@@ -133,45 +336,105 @@ impl KeyboardManager {
                 self.logo = modifiers.super_key();
             }
             Event::MainEventsCleared => {
+                // Take the queued events so that iterating over them doesn't keep `self`
+                // borrowed, since dispatching a keybinding string needs `&mut self`.
+                let key_events = std::mem::take(&mut self.queued_key_events);
+
                 // And the window wasn't just focused.
                 if !self.ignore_input_this_frame {
                     // If we have a keyboard event this frame
-                    for key_event in self.queued_key_events.iter() {
-                        // And a key was pressed
-                        if key_event.state == ElementState::Pressed {
+                    for key_event in key_events.iter() {
+                        // And a key was pressed, and the IME isn't in the middle of composing a
+                        // character (in which case we wait for the eventual Ime::Commit instead).
+                        if key_event.state == ElementState::Pressed && !self.ime_active {
+                            let produced_text = key_event.text_with_all_modifiers();
+                            let (altgr_ctrl, altgr_alt) =
+                                strip_altgr(self.ctrl, self.alt, produced_text);
+                            let is_altgr = (altgr_ctrl, altgr_alt) != (self.ctrl, self.alt);
+
+                            // Prefer the physical key position over the logical key while a
+                            // modifier is held, so `<C-...>` chords stay layout-independent.
+                            let physical_key_text =
+                                if (self.ctrl || self.alt || self.logo) && !is_altgr {
+                                    physical_key_to_text(key_event.physical_key)
+                                } else {
+                                    None
+                                };
+
                             // Determine if this key event represents a key which won't ever
                             // present text.
-                            if let Some(key_text) = is_control_key(key_event.logical_key) {
+                            if is_altgr {
+                                // AltGr was reported as Ctrl+Alt but still produced printable
+                                // text, so send the bare character instead of a bogus chord.
+                                let saved_ctrl = self.ctrl;
+                                let saved_alt = self.alt;
+                                self.ctrl = altgr_ctrl;
+                                self.alt = altgr_alt;
+                                self.send_text(produced_text.expect("checked by strip_altgr"));
+                                self.ctrl = saved_ctrl;
+                                self.alt = saved_alt;
+                            } else if let Some(key_text) = physical_key_text {
+                                let keybinding_string =
+                                    self.format_keybinding_string(false, key_text);
+                                self.send_keybinding_string(keybinding_string);
+                            } else if let Some(key_text) = is_control_key(key_event.logical_key) {
                                 let keybinding_string =
                                     self.format_keybinding_string(true, key_text);
-
-                                self.command_sender
-                                    .send(UiCommand::Keyboard(keybinding_string))
-                                    .expect("Could not send keyboard ui command");
-                            } else if let Some(key_text) = key_event.text_with_all_modifiers() {
+                                self.send_keybinding_string(keybinding_string);
+                            } else if let Some(key_text) = produced_text {
                                 // This is not a control key, so we rely upon winit to determine if
                                 // this is a deadkey or not.
-                                let keybinding_string =
-                                    if let Some(escaped_text) = is_special(key_text) {
-                                        self.format_keybinding_string(true, escaped_text)
-                                    } else {
-                                        self.format_keybinding_string(false, key_text)
-                                    };
-
-                                self.command_sender
-                                    .send(UiCommand::Keyboard(keybinding_string))
-                                    .expect("Could not send keyboard ui command");
+                                self.send_text(key_text);
                             }
                         }
                     }
                 }
 
+                // Flush one macro event per frame, as if it had just been typed.
+                let mut replay_finished = false;
+                if let RecordState::Replaying { events, idx } = &mut self.record_state {
+                    if let Some(keybinding_string) = events.get(*idx).cloned() {
+                        self.command_sender
+                            .send(UiCommand::Keyboard(keybinding_string))
+                            .expect("Could not send keyboard ui command");
+                    }
+
+                    *idx += 1;
+                    replay_finished = *idx >= events.len();
+                }
+                if replay_finished {
+                    self.record_state = RecordState::Idle;
+                }
+
                 // Regardless of whether this was a valid keyboard input or not, rest ignoring and
                 // whatever event was queued.
                 self.ignore_input_this_frame = false;
-                self.queued_key_events.clear();
             }
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_altgr_clears_modifiers_for_composed_text() {
+        assert_eq!(strip_altgr(true, true, Some("@")), (false, false));
+        assert_eq!(strip_altgr(true, true, Some("{")), (false, false));
+        assert_eq!(strip_altgr(true, true, Some("|")), (false, false));
+    }
+
+    #[test]
+    fn strip_altgr_leaves_genuine_chords_alone() {
+        assert_eq!(strip_altgr(true, true, None), (true, true));
+        assert_eq!(strip_altgr(true, false, Some("c")), (true, false));
+        assert_eq!(strip_altgr(false, true, Some("c")), (false, true));
+    }
+
+    #[test]
+    fn strip_altgr_ignores_control_characters() {
+        assert_eq!(strip_altgr(true, true, Some("\u{8}")), (true, true));
+    }
+}